@@ -0,0 +1,5 @@
+// Embedded migrations are read at compile time by `sqlx::migrate!`, so make
+// sure adding or editing a migration triggers a rebuild.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
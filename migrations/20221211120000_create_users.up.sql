@@ -0,0 +1,7 @@
+CREATE TABLE users (
+    username TEXT NOT NULL,
+    email TEXT NOT NULL,
+    bio TEXT NOT NULL DEFAULT '',
+    CONSTRAINT user_username_key UNIQUE (username),
+    CONSTRAINT user_email_key UNIQUE (email)
+);
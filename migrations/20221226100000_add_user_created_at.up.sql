@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+-- Keyset pagination sorted by creation time breaks ties on username.
+CREATE INDEX users_created_at_idx ON users (created_at, username);
@@ -0,0 +1,7 @@
+DROP INDEX users_email_trgm_idx;
+DROP INDEX users_username_trgm_idx;
+DROP INDEX users_search_idx;
+
+ALTER TABLE users DROP COLUMN search;
+
+-- pg_trgm stays installed, other schemas may rely on it.
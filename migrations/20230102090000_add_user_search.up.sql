@@ -0,0 +1,11 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+ALTER TABLE users ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', username), 'A') ||
+    setweight(to_tsvector('english', email), 'B') ||
+    setweight(to_tsvector('english', bio), 'C')
+) STORED;
+
+CREATE INDEX users_search_idx ON users USING GIN (search);
+CREATE INDEX users_username_trgm_idx ON users USING GIN (username gin_trgm_ops);
+CREATE INDEX users_email_trgm_idx ON users USING GIN (email gin_trgm_ops);
@@ -0,0 +1,3 @@
+ALTER TABLE users DROP CONSTRAINT users_pkey;
+
+ALTER TABLE users DROP COLUMN id;
@@ -0,0 +1,5 @@
+-- Usernames can change, the id never does; other services should hold on
+-- to the id.
+ALTER TABLE users ADD COLUMN id UUID NOT NULL DEFAULT gen_random_uuid();
+
+ALTER TABLE users ADD CONSTRAINT users_pkey PRIMARY KEY (id);
@@ -0,0 +1,2 @@
+-- Bumped on every change to a user, it backs the `ETag` of the resource.
+ALTER TABLE users ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
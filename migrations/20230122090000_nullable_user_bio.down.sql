@@ -0,0 +1,12 @@
+DROP INDEX users_search_idx;
+ALTER TABLE users DROP COLUMN search;
+
+UPDATE users SET bio = '' WHERE bio IS NULL;
+ALTER TABLE users ALTER COLUMN bio SET DEFAULT '', ALTER COLUMN bio SET NOT NULL;
+
+ALTER TABLE users ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', username), 'A') ||
+    setweight(to_tsvector('english', email), 'B') ||
+    setweight(to_tsvector('english', bio), 'C')
+) STORED;
+CREATE INDEX users_search_idx ON users USING GIN (search);
@@ -0,0 +1,12 @@
+ALTER TABLE users ALTER COLUMN bio DROP NOT NULL, ALTER COLUMN bio DROP DEFAULT;
+
+-- A NULL bio would null the whole search vector, so rebuild it with a
+-- coalesced bio.
+DROP INDEX users_search_idx;
+ALTER TABLE users DROP COLUMN search;
+ALTER TABLE users ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', username), 'A') ||
+    setweight(to_tsvector('english', email), 'B') ||
+    setweight(to_tsvector('english', coalesce(bio, '')), 'C')
+) STORED;
+CREATE INDEX users_search_idx ON users USING GIN (search);
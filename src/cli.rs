@@ -0,0 +1,148 @@
+use crate::db::MIGRATOR;
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use sqlx::migrate::Migrate;
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to a TOML config file (defaults to `$APP_CONFIG` or `./config.toml`)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Manage the database schema without starting the HTTP server
+    Migrate {
+        #[command(subcommand)]
+        command: MigrateCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MigrateCommand {
+    /// Apply all pending migrations
+    Up,
+    /// Revert the most recently applied migrations
+    Down {
+        /// Number of migrations to revert
+        #[arg(default_value_t = 1)]
+        n: usize,
+    },
+    /// List embedded migrations and whether they are applied
+    Status,
+    /// Create a new reversible migration in the migrations directory
+    ///
+    /// Migrations are embedded at compile time, so the binary has to be
+    /// rebuilt before `migrate up` picks the new files up.
+    New {
+        /// Short description, used as the file name suffix
+        name: String,
+
+        /// Directory the migration files are written to
+        #[arg(long, default_value = "migrations")]
+        source: PathBuf,
+    },
+}
+
+pub async fn migrate(command: MigrateCommand, database_url: &str) -> anyhow::Result<()> {
+    match command {
+        MigrateCommand::Up => {
+            let pool = connect(database_url).await?;
+            MIGRATOR.run(&pool).await?;
+            println!("database is up to date");
+        }
+        MigrateCommand::Down { n } => {
+            let pool = connect(database_url).await?;
+            let mut conn = pool.acquire().await?;
+            conn.ensure_migrations_table().await?;
+            let applied = conn.list_applied_migrations().await?;
+            drop(conn);
+
+            // Everything newer than `target` is reverted.
+            let target = applied
+                .len()
+                .checked_sub(n + 1)
+                .map(|i| applied[i].version)
+                .unwrap_or(0);
+            MIGRATOR.undo(&pool, target).await?;
+            println!(
+                "reverted {} migration(s)",
+                applied.iter().filter(|m| m.version > target).count()
+            );
+        }
+        MigrateCommand::Status => {
+            let pool = connect(database_url).await?;
+            let mut conn = pool.acquire().await?;
+            conn.ensure_migrations_table().await?;
+            if let Some(version) = conn.dirty_version().await? {
+                println!("{version:<16} dirty (partially applied)");
+            }
+            let applied: HashMap<_, _> = conn
+                .list_applied_migrations()
+                .await?
+                .into_iter()
+                .map(|m| (m.version, m.checksum))
+                .collect();
+
+            for migration in MIGRATOR
+                .iter()
+                .filter(|m| !m.migration_type.is_down_migration())
+            {
+                let state = match applied.get(&migration.version) {
+                    Some(checksum) if *checksum == migration.checksum => "applied",
+                    Some(_) => "checksum mismatch",
+                    None => "pending",
+                };
+                println!(
+                    "{:<16} {:<18} {}",
+                    migration.version, state, migration.description
+                );
+            }
+            for version in applied.keys() {
+                if !MIGRATOR.iter().any(|m| m.version == *version) {
+                    println!("{version:<16} {:<18} (not in this binary)", "missing");
+                }
+            }
+        }
+        MigrateCommand::New { name, source } => {
+            let name = name.trim().to_lowercase().replace([' ', '-'], "_");
+            if name.is_empty() {
+                bail!("migration name must not be empty");
+            }
+            let version = OffsetDateTime::now_utc()
+                .format(format_description!(
+                    "[year][month][day][hour][minute][second]"
+                ))
+                .context("formatting migration version")?;
+
+            std::fs::create_dir_all(&source)
+                .with_context(|| format!("creating {}", source.display()))?;
+            for (kind, body) in [
+                ("up", "-- Add up migration script here\n"),
+                ("down", "-- Add down migration script here\n"),
+            ] {
+                let path = source.join(format!("{version}_{name}.{kind}.sql"));
+                std::fs::write(&path, body)
+                    .with_context(|| format!("writing {}", path.display()))?;
+                println!("created {}", path.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn connect(database_url: &str) -> anyhow::Result<PgPool> {
+    PgPool::connect(database_url)
+        .await
+        .context("can connect to database")
+}
@@ -0,0 +1,85 @@
+use crate::config::{Config, ErrorFormat};
+use axum::{
+    extract::State,
+    http::{header, HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use tracing::Instrument;
+use uuid::Uuid;
+
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static CONTEXT: RequestContext;
+}
+
+/// Per-request data that code without access to the request, such as
+/// `Error::into_response`, needs to see.
+#[derive(Clone)]
+pub struct RequestContext {
+    pub request_id: String,
+    /// Path of the request, used as the problem `instance`.
+    pub path: String,
+    pub error_format: ErrorFormat,
+    pub problem_type_base: String,
+}
+
+impl RequestContext {
+    /// Context of the request currently being handled, if any.
+    pub fn current() -> Option<Self> {
+        CONTEXT.try_with(Clone::clone).ok()
+    }
+}
+
+/// Middleware that assigns every request an ID, reusing a caller-supplied
+/// `x-request-id` header when present, and echoes it on the response.
+///
+/// Everything logged while handling the request is recorded inside a span
+/// carrying the ID, so log lines can be matched to client reports.
+///
+/// Errors are rendered as `errors.format`, unless the client accepts
+/// `application/problem+json`.
+pub async fn layer<B>(
+    State(config): State<Arc<Config>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let request_id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!(
+        "request",
+        %request_id,
+        method = %req.method(),
+        uri = %req.uri(),
+    );
+    let accepts_problem = req
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("application/problem+json"));
+    let context = RequestContext {
+        request_id: request_id.clone(),
+        path: req.uri().path().to_string(),
+        error_format: if accepts_problem {
+            ErrorFormat::Problem
+        } else {
+            config.errors.format
+        },
+        problem_type_base: config.errors.problem_type_base.clone(),
+    };
+    let mut response = CONTEXT.scope(context, next.run(req)).instrument(span).await;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    }
+    response
+}
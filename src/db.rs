@@ -0,0 +1,182 @@
+use crate::config::DatabaseConfig;
+use rand::Rng;
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Schema migrations from `./migrations`, embedded into the binary.
+///
+/// Applied versions and their checksums are recorded in `_sqlx_migrations`;
+/// running the migrator against a database whose applied migrations differ
+/// from the embedded ones fails instead of silently continuing.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Builds pool options from the `[database]` config section.
+///
+/// Session settings (`statement_timeout`, `application_name`,
+/// `search_path`) are applied with `set_config` on every new connection.
+pub fn pool_options(config: &DatabaseConfig) -> PgPoolOptions {
+    let mut settings = Vec::new();
+    if let Some(timeout) = config.statement_timeout {
+        settings.push(("statement_timeout", format!("{}ms", timeout.as_millis())));
+    }
+    if let Some(name) = &config.application_name {
+        settings.push(("application_name", name.clone()));
+    }
+    if let Some(path) = &config.search_path {
+        settings.push(("search_path", path.clone()));
+    }
+    let settings = Arc::new(settings);
+
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(config.acquire_timeout)
+        .idle_timeout(config.idle_timeout)
+        .max_lifetime(config.max_lifetime)
+        .test_before_acquire(config.test_before_acquire)
+        .after_connect(move |conn, _meta| {
+            let settings = settings.clone();
+            Box::pin(async move {
+                for (name, value) in settings.iter() {
+                    sqlx::query("SELECT set_config($1, $2, false)")
+                        .bind(name)
+                        .bind(value)
+                        .execute(&mut *conn)
+                        .await?;
+                }
+                Ok(())
+            })
+        })
+}
+
+/// Set once the database has been reached and all migrations applied.
+#[derive(Clone, Default)]
+pub struct Migrated(Arc<AtomicBool>);
+
+impl Migrated {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    fn set(&self) {
+        self.0.store(true, Ordering::Release)
+    }
+}
+
+/// Why the database could not be prepared at startup.
+#[derive(thiserror::Error, Debug)]
+pub enum StartupError {
+    #[error("database not reachable within the connect deadline")]
+    Unreachable,
+
+    #[error("can't connect to the database: {0}")]
+    Connect(#[source] sqlx::Error),
+
+    #[error("can't apply database migrations: {0}")]
+    Migrate(#[from] MigrateError),
+}
+
+/// Waits for the database, then applies migrations.
+///
+/// Only reaching the database is retried, with exponential backoff and
+/// jitter, and only that is bounded by `deadline`; without a deadline it
+/// retries forever. Migrations then run to completion however long they
+/// take. Errors that retrying cannot fix, such as a wrong password or a
+/// checksum mismatch, are returned immediately.
+pub async fn migrate_with_retry(
+    pool: &PgPool,
+    config: &DatabaseConfig,
+    deadline: Option<Instant>,
+    migrated: &Migrated,
+) -> Result<(), StartupError> {
+    wait_for_database(pool, config, deadline).await?;
+    MIGRATOR.run(pool).await?;
+    migrated.set();
+    Ok(())
+}
+
+async fn wait_for_database(
+    pool: &PgPool,
+    config: &DatabaseConfig,
+    deadline: Option<Instant>,
+) -> Result<(), StartupError> {
+    let mut backoff = config.retry_initial_backoff;
+    loop {
+        let attempt = ping(pool);
+        let result = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, attempt)
+                .await
+                .map_err(|_| StartupError::Unreachable)?,
+            None => attempt.await,
+        };
+
+        let err = match result {
+            Ok(()) => return Ok(()),
+            Err(e) if is_transient(&e) => e,
+            Err(e) => return Err(StartupError::Connect(e)),
+        };
+
+        // Sleep somewhere between half and the full backoff so that replicas
+        // started together don't retry in lockstep.
+        let sleep = backoff / 2 + rand::thread_rng().gen_range(Duration::ZERO..=backoff / 2);
+        if deadline.is_some_and(|deadline| Instant::now() + sleep >= deadline) {
+            return Err(StartupError::Connect(err));
+        }
+        tracing::warn!("database not ready ({err}), retrying in {sleep:?}");
+        tokio::time::sleep(sleep).await;
+        backoff = (backoff * 2).min(config.retry_max_backoff);
+    }
+}
+
+async fn ping(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    sqlx::query("SELECT 1").execute(&mut conn).await?;
+    Ok(())
+}
+
+fn is_transient(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        // connection_exception (08xxx) and cannot_connect_now (57P03)
+        sqlx::Error::Database(e) => e
+            .code()
+            .is_some_and(|code| code.starts_with("08") || code == "57P03"),
+        _ => false,
+    }
+}
+
+/// Runs `sql`, a `DELETE` taking the retention in seconds as `$1`, every
+/// `interval` once migrations are applied. `what` names the purged rows in
+/// the logs.
+pub async fn purge_periodically(
+    pool: PgPool,
+    migrated: Migrated,
+    sql: &'static str,
+    retention: Duration,
+    interval: Duration,
+    what: &'static str,
+) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if !migrated.get() {
+            continue;
+        }
+        let result = sqlx::query(sql)
+            .bind(retention.as_secs_f64())
+            .execute(&pool)
+            .await;
+        match result {
+            Ok(done) if done.rows_affected() > 0 => {
+                tracing::info!("purged {} {what}", done.rows_affected())
+            }
+            Ok(_) => {}
+            Err(err) => tracing::warn!("failed to purge {what}: {err}"),
+        }
+    }
+}
@@ -1,20 +1,46 @@
-use axum::http::StatusCode;
+use crate::config::ErrorFormat;
+use crate::context::RequestContext;
+use axum::http::{header, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
 use sqlx::error::DatabaseError;
+use sqlx::postgres::PgDatabaseError;
 use std::borrow::Cow;
 use std::collections::HashMap;
 
+pub type FieldErrors = HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("request path not found")]
     NotFound,
 
     #[error("error in the request body")]
-    UnprocessableEntity {
-        errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
+    UnprocessableEntity { errors: FieldErrors },
+
+    #[error("{message}")]
+    BadRequest {
+        message: Cow<'static, str>,
+        errors: FieldErrors,
     },
 
     #[error("{0}")]
+    Conflict(Cow<'static, str>),
+
+    #[error("the resource has changed since it was last read")]
+    PreconditionFailed,
+
+    #[error("the request body is too large")]
+    PayloadTooLarge,
+
+    #[error("the service is temporarily unavailable")]
+    ServiceUnavailable,
+
+    #[error("the request conflicted with a concurrent one and can be retried")]
+    Retryable,
+
+    #[error("an error occurred with the database")]
     Sqlx(#[from] sqlx::Error),
 
     #[error("an internal server error occurred")]
@@ -27,29 +53,250 @@ impl Error {
         K: Into<Cow<'static, str>>,
         V: Into<Cow<'static, str>>,
     {
-        let mut error_map = HashMap::new();
+        Self::UnprocessableEntity {
+            errors: field_errors(errors),
+        }
+    }
 
-        for (key, val) in errors {
-            error_map
-                .entry(key.into())
-                .or_insert_with(Vec::new)
-                .push(val.into());
+    pub fn bad_request<K, V>(
+        message: impl Into<Cow<'static, str>>,
+        errors: impl IntoIterator<Item = (K, V)>,
+    ) -> Self
+    where
+        K: Into<Cow<'static, str>>,
+        V: Into<Cow<'static, str>>,
+    {
+        Self::BadRequest {
+            message: message.into(),
+            errors: field_errors(errors),
         }
+    }
 
-        Self::UnprocessableEntity { errors: error_map }
+    /// The default mapping for database errors no handler claimed, based on
+    /// the SQLSTATE class. Anything unrecognised stays an internal error.
+    fn from_sqlx(err: sqlx::Error) -> Self {
+        let dbe = match &err {
+            sqlx::Error::PoolTimedOut => {
+                tracing::warn!("{}", chain(&err));
+                return Self::ServiceUnavailable;
+            }
+            sqlx::Error::Database(dbe) => dbe,
+            _ => return Self::Sqlx(err),
+        };
+        let column = dbe
+            .try_downcast_ref::<PgDatabaseError>()
+            .and_then(PgDatabaseError::column)
+            .map(str::to_string);
+
+        match DbErrorKind::of(dbe.as_ref()) {
+            Some(DbErrorKind::UniqueViolation) => {
+                Self::Conflict("a resource with the same unique value already exists".into())
+            }
+            Some(DbErrorKind::ForeignKeyViolation) => Self::Conflict(
+                "the request references a resource that does not exist or is still referenced"
+                    .into(),
+            ),
+            Some(DbErrorKind::CheckViolation) => Self::bad_request(
+                "a value is outside of its allowed range",
+                column.map(|c| (c, "invalid value")),
+            ),
+            Some(DbErrorKind::NotNullViolation) => Self::bad_request(
+                "a required value is missing",
+                column.map(|c| (c, "must not be null")),
+            ),
+            Some(DbErrorKind::SerializationFailure | DbErrorKind::DeadlockDetected) => {
+                tracing::warn!("{}", chain(&err));
+                Self::Retryable
+            }
+            Some(DbErrorKind::QueryCanceled) => {
+                tracing::warn!("{}", chain(&err));
+                Self::ServiceUnavailable
+            }
+            None => Self::Sqlx(err),
+        }
     }
-}
 
-impl IntoResponse for Error {
-    fn into_response(self) -> Response {
-        let status_code = match self {
+    fn status_code(&self) -> StatusCode {
+        match self {
             Self::NotFound => StatusCode::NOT_FOUND,
             Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Retryable => StatusCode::SERVICE_UNAVAILABLE,
             Self::Sqlx(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable, machine-readable identifier clients can match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::UnprocessableEntity { .. } => "unprocessable_entity",
+            Self::BadRequest { .. } => "bad_request",
+            Self::Conflict(_) => "conflict",
+            Self::PreconditionFailed => "precondition_failed",
+            Self::PayloadTooLarge => "payload_too_large",
+            Self::ServiceUnavailable => "service_unavailable",
+            Self::Retryable => "retryable",
+            Self::Sqlx(_) => "internal_error",
+            Self::Anyhow(_) => "internal_error",
+        }
+    }
+}
+
+fn field_errors<K, V>(errors: impl IntoIterator<Item = (K, V)>) -> FieldErrors
+where
+    K: Into<Cow<'static, str>>,
+    V: Into<Cow<'static, str>>,
+{
+    let mut error_map = HashMap::new();
+
+    for (key, val) in errors {
+        error_map
+            .entry(key.into())
+            .or_insert_with(Vec::new)
+            .push(val.into());
+    }
+
+    error_map
+}
+
+/// Postgres error conditions with a client-facing meaning, see
+/// <https://www.postgresql.org/docs/current/errcodes-appendix.html>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DbErrorKind {
+    UniqueViolation,
+    ForeignKeyViolation,
+    CheckViolation,
+    NotNullViolation,
+    SerializationFailure,
+    DeadlockDetected,
+    QueryCanceled,
+}
+
+impl DbErrorKind {
+    pub fn of(err: &dyn DatabaseError) -> Option<Self> {
+        match err.code()?.as_ref() {
+            "23505" => Some(Self::UniqueViolation),
+            "23503" => Some(Self::ForeignKeyViolation),
+            "23514" => Some(Self::CheckViolation),
+            "23502" => Some(Self::NotNullViolation),
+            "40001" => Some(Self::SerializationFailure),
+            "40P01" => Some(Self::DeadlockDetected),
+            "57014" => Some(Self::QueryCanceled),
+            _ => None,
+        }
+    }
+}
+
+/// The JSON body of every error response.
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a FieldErrors>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+/// The `application/problem+json` body (RFC 7807), with the fields of
+/// [`ErrorBody`] as extension members.
+#[derive(Serialize)]
+struct ProblemBody<'a> {
+    #[serde(rename = "type")]
+    type_: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    instance: String,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a FieldErrors>,
+    request_id: String,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let context = RequestContext::current();
+        let this = match self {
+            Self::Sqlx(err) => Self::from_sqlx(err),
+            this => this,
+        };
+
+        // Internal details go to the log only; the client gets a generic
+        // message plus the request ID to quote when reporting the problem.
+        let message = match &this {
+            Self::Sqlx(e) => {
+                tracing::error!(error = ?e, "{}", chain(&this));
+                "an internal server error occurred".to_string()
+            }
+            Self::Anyhow(e) => {
+                tracing::error!("{e:?}");
+                "an internal server error occurred".to_string()
+            }
+            _ => this.to_string(),
+        };
+        let errors = match &this {
+            Self::UnprocessableEntity { errors } => Some(errors),
+            Self::BadRequest { errors, .. } if !errors.is_empty() => Some(errors),
+            _ => None,
         };
-        (status_code, format!("Something went wrong: {}", self)).into_response()
+        let status_code = this.status_code();
+
+        let mut response = match context {
+            Some(context) if context.error_format == ErrorFormat::Problem => {
+                let body = ProblemBody {
+                    type_: format!("{}{}", context.problem_type_base, this.code()),
+                    title: status_code.canonical_reason().unwrap_or_default(),
+                    status: status_code.as_u16(),
+                    detail: message,
+                    instance: context.path,
+                    code: this.code(),
+                    errors,
+                    request_id: context.request_id,
+                };
+                let mut response = (status_code, Json(body)).into_response();
+                response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/problem+json"),
+                );
+                response
+            }
+            context => {
+                let body = ErrorBody {
+                    code: this.code(),
+                    message,
+                    errors,
+                    request_id: context.map(|c| c.request_id),
+                };
+                (status_code, Json(body)).into_response()
+            }
+        };
+
+        if matches!(this, Self::ServiceUnavailable | Self::Retryable) {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+        }
+        response
+    }
+}
+
+/// Formats an error followed by all of its sources, `outer: inner: ...`.
+fn chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
     }
+    message
 }
 
 pub trait ResultExt<T> {
@@ -58,6 +305,14 @@ pub trait ResultExt<T> {
         name: &str,
         f: impl FnOnce(Box<dyn DatabaseError>) -> Error,
     ) -> Result<T, Error>;
+
+    /// Like [`on_constraint`](Self::on_constraint), but matches every error
+    /// of the given SQLSTATE class, e.g. any unique violation.
+    fn on_db_error(
+        self,
+        kind: DbErrorKind,
+        f: impl FnOnce(Box<dyn DatabaseError>) -> Error,
+    ) -> Result<T, Error>;
 }
 
 impl<T, E> ResultExt<T> for Result<T, E>
@@ -76,4 +331,108 @@ where
             e => e,
         })
     }
+
+    fn on_db_error(
+        self,
+        kind: DbErrorKind,
+        map_err: impl FnOnce(Box<dyn DatabaseError>) -> Error,
+    ) -> Result<T, Error> {
+        self.map_err(|e| match e.into() {
+            Error::Sqlx(sqlx::Error::Database(dbe))
+                if DbErrorKind::of(dbe.as_ref()) == Some(kind) =>
+            {
+                map_err(dbe)
+            }
+            e => e,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database error with just a SQLSTATE and a constraint name.
+    #[derive(Debug)]
+    struct FakeDbError {
+        code: &'static str,
+        constraint: Option<&'static str>,
+    }
+
+    impl std::fmt::Display for FakeDbError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "SQLSTATE {}", self.code)
+        }
+    }
+
+    impl std::error::Error for FakeDbError {}
+
+    impl DatabaseError for FakeDbError {
+        fn message(&self) -> &str {
+            "fake"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(self.code.into())
+        }
+
+        fn constraint(&self) -> Option<&str> {
+            self.constraint
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+    }
+
+    fn db_error(code: &'static str, constraint: Option<&'static str>) -> Result<(), sqlx::Error> {
+        Err(sqlx::Error::Database(Box::new(FakeDbError {
+            code,
+            constraint,
+        })))
+    }
+
+    #[test]
+    fn classifies_sqlstates() {
+        let kind = |code| {
+            DbErrorKind::of(&FakeDbError {
+                code,
+                constraint: None,
+            })
+        };
+        assert_eq!(kind("23505"), Some(DbErrorKind::UniqueViolation));
+        assert_eq!(kind("40P01"), Some(DbErrorKind::DeadlockDetected));
+        assert_eq!(kind("57014"), Some(DbErrorKind::QueryCanceled));
+        assert_eq!(kind("42P01"), None);
+    }
+
+    #[test]
+    fn maps_errors_of_the_given_kind() {
+        let result = db_error("23505", Some("user_email_key"))
+            .on_db_error(DbErrorKind::UniqueViolation, |_| Error::NotFound);
+        assert!(matches!(result, Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn leaves_errors_of_other_kinds_alone() {
+        let result =
+            db_error("23503", None).on_db_error(DbErrorKind::UniqueViolation, |_| Error::NotFound);
+        assert!(matches!(result, Err(Error::Sqlx(_))));
+    }
+
+    #[test]
+    fn maps_errors_of_the_given_constraint() {
+        let result = db_error("23505", Some("user_email_key"))
+            .on_constraint("user_username_key", |_| Error::NotFound)
+            .on_constraint("user_email_key", |_| Error::PreconditionFailed);
+        assert!(matches!(result, Err(Error::PreconditionFailed)));
+    }
 }
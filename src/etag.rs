@@ -0,0 +1,139 @@
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use std::fmt;
+
+/// A strong entity tag, kept with its surrounding quotes.
+pub struct ETag(String);
+
+impl ETag {
+    pub fn new(opaque: impl fmt::Display) -> Self {
+        Self(format!("\"{opaque}\""))
+    }
+
+    pub fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.0).expect("entity tags are visible ASCII")
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Evaluates `If-Match` (RFC 9110, section 13.1.1) with the strong
+/// comparison. A request without the header always passes.
+pub fn if_match(headers: &HeaderMap, etag: &ETag) -> bool {
+    match tags(headers, header::IF_MATCH) {
+        None => true,
+        Some(tags) => tags
+            .iter()
+            .any(|tag| *tag == "*" || (!tag.starts_with("W/") && *tag == etag.0)),
+    }
+}
+
+/// Evaluates `If-None-Match` (RFC 9110, section 13.1.2) with the weak
+/// comparison, returning `true` when the client's copy is still current.
+pub fn if_none_match(headers: &HeaderMap, etag: &ETag) -> bool {
+    match tags(headers, header::IF_NONE_MATCH) {
+        None => false,
+        Some(tags) => tags
+            .iter()
+            .any(|tag| *tag == "*" || tag.trim_start_matches("W/") == etag.0),
+    }
+}
+
+/// The entity tags listed in all occurrences of a conditional header, or
+/// `None` if the request does not carry it.
+fn tags(headers: &HeaderMap, name: HeaderName) -> Option<Vec<&str>> {
+    let mut values = headers.get_all(name).iter().peekable();
+    values.peek()?;
+    Some(
+        values
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: HeaderName, values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn etag() -> ETag {
+        ETag::new("abc")
+    }
+
+    #[test]
+    fn passes_without_the_headers() {
+        assert!(if_match(&HeaderMap::new(), &etag()));
+        assert!(!if_none_match(&HeaderMap::new(), &etag()));
+    }
+
+    #[test]
+    fn compares_if_match_strongly() {
+        assert!(if_match(&headers(header::IF_MATCH, &["\"abc\""]), &etag()));
+        assert!(!if_match(
+            &headers(header::IF_MATCH, &["W/\"abc\""]),
+            &etag()
+        ));
+        assert!(!if_match(&headers(header::IF_MATCH, &["\"xyz\""]), &etag()));
+    }
+
+    #[test]
+    fn compares_if_none_match_weakly() {
+        let current = |value| if_none_match(&headers(header::IF_NONE_MATCH, &[value]), &etag());
+        assert!(current("\"abc\""));
+        assert!(current("W/\"abc\""));
+        assert!(!current("\"xyz\""));
+    }
+
+    #[test]
+    fn matches_any_tag_with_a_star() {
+        assert!(if_match(&headers(header::IF_MATCH, &["*"]), &etag()));
+        assert!(if_none_match(
+            &headers(header::IF_NONE_MATCH, &["*"]),
+            &etag()
+        ));
+    }
+
+    #[test]
+    fn matches_a_tag_in_a_list() {
+        let list = ["\"xyz\", \"abc\""];
+        assert!(if_match(&headers(header::IF_MATCH, &list), &etag()));
+        assert!(if_none_match(
+            &headers(header::IF_NONE_MATCH, &list),
+            &etag()
+        ));
+        let list = ["\"xyz\", W/\"uvw\""];
+        assert!(!if_match(&headers(header::IF_MATCH, &list), &etag()));
+        assert!(!if_none_match(
+            &headers(header::IF_NONE_MATCH, &list),
+            &etag()
+        ));
+    }
+
+    #[test]
+    fn matches_a_tag_on_another_header_line() {
+        let lines = ["\"xyz\"", "\"abc\""];
+        assert!(if_match(&headers(header::IF_MATCH, &lines), &etag()));
+        assert!(if_none_match(
+            &headers(header::IF_NONE_MATCH, &lines),
+            &etag()
+        ));
+    }
+
+    #[test]
+    fn fails_an_empty_if_match() {
+        assert!(!if_match(&headers(header::IF_MATCH, &[""]), &etag()));
+    }
+}
@@ -0,0 +1,283 @@
+//! Extractors whose rejections are reported through [`Error`], so malformed
+//! requests get the same error format as everything else.
+
+use crate::error::Error;
+use crate::patch::{self, Patch};
+use axum::{
+    async_trait,
+    body::{Bytes, HttpBody},
+    extract::{rejection::PathRejection, FromRequest, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, Request, StatusCode},
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use validator::{Validate, ValidationErrors};
+
+/// JSON request body; also usable as a response like `axum::Json`.
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_json(req.headers()) {
+            return Err(Error::bad_request(
+                "expected a request with `Content-Type: application/json`",
+                [("content-type", "must be application/json")],
+            ));
+        }
+        let bytes = read_body(req, state).await?;
+        from_slice(&bytes).map(Json)
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// A JSON body that has passed its `#[validate(...)]` rules.
+///
+/// Every failing field is reported at once as
+/// [`Error::UnprocessableEntity`], before the handler runs.
+pub struct ValidatedJson<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        value.validate().map_err(validation_error)?;
+        Ok(Self(value))
+    }
+}
+
+/// Path parameters; a parameter that fails to parse is reported by name.
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        use axum::extract::path::ErrorKind;
+
+        match axum::extract::Path::<T>::from_request_parts(parts, state).await {
+            Ok(axum::extract::Path(value)) => Ok(Self(value)),
+            Err(PathRejection::FailedToDeserializePathParams(e)) => {
+                let field = match e.kind() {
+                    ErrorKind::ParseErrorAtKey { key, .. }
+                    | ErrorKind::InvalidUtf8InPathParam { key } => key.clone(),
+                    _ => "path".to_string(),
+                };
+                Err(Error::bad_request(
+                    "invalid path parameter",
+                    [(field, e.kind().to_string())],
+                ))
+            }
+            Err(e) => Err(anyhow::anyhow!(e.body_text()).into()),
+        }
+    }
+}
+
+/// Query string parameters; a parameter that fails to parse is reported by
+/// name.
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let deserializer =
+            serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+        serde_path_to_error::deserialize(deserializer)
+            .map(Query)
+            .map_err(|err| {
+                let (field, message) = describe(err.path(), err.inner());
+                Error::bad_request("invalid query parameter", [(field, message)])
+            })
+    }
+}
+
+/// The raw request body, for handlers that parse it themselves; failing to
+/// read it is reported like [`Json`] does.
+pub struct RawBody(pub Bytes);
+
+#[async_trait]
+impl<S, B> FromRequest<S, B> for RawBody
+where
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        read_body(req, state).await.map(RawBody)
+    }
+}
+
+/// A JSON Merge Patch or JSON Patch body, told apart by its content type.
+#[async_trait]
+impl<S, B> FromRequest<S, B> for Patch
+where
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let essence = media_type(req.headers());
+        match essence.as_deref() {
+            Some(patch::MERGE_PATCH) => {
+                Ok(Patch::Merge(from_slice(&read_body(req, state).await?)?))
+            }
+            Some(patch::JSON_PATCH) => Ok(Patch::Json(from_slice(&read_body(req, state).await?)?)),
+            _ => Err(Error::bad_request(
+                "expected a request with `Content-Type: application/merge-patch+json` \
+                 or `application/json-patch+json`",
+                [(
+                    "content-type",
+                    "must be application/merge-patch+json or application/json-patch+json",
+                )],
+            )),
+        }
+    }
+}
+
+/// Deserializes and validates a JSON value built by the server, such as a
+/// patched document, reporting problems like a request body would be.
+pub fn from_value<T: DeserializeOwned + Validate>(value: Value) -> Result<T, Error> {
+    let value: T = serde_path_to_error::deserialize(value).map_err(|err| {
+        let (field, message) = describe(err.path(), err.inner());
+        Error::unprocessable_entity([(field, message)])
+    })?;
+    value.validate().map_err(validation_error)?;
+    Ok(value)
+}
+
+async fn read_body<S, B>(req: Request<B>, state: &S) -> Result<Bytes, Error>
+where
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    Bytes::from_request(req, state).await.map_err(|e| {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            Error::PayloadTooLarge
+        } else {
+            Error::bad_request(e.body_text(), [("body", "could not be read")])
+        }
+    })
+}
+
+/// Deserializes a JSON document, reporting problems like [`Json`] does.
+pub fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(bytes);
+    serde_path_to_error::deserialize(deserializer).map_err(|err| {
+        let (field, message) = describe(err.path(), err.inner());
+        match err.inner().classify() {
+            serde_json::error::Category::Data => Error::unprocessable_entity([(field, message)]),
+            _ => Error::bad_request("the request body is not valid JSON", [(field, message)]),
+        }
+    })
+}
+
+/// The lowercased media type of the request body, without parameters.
+pub fn media_type(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    Some(
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase(),
+    )
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    let Some(essence) = media_type(headers) else {
+        return false;
+    };
+    essence == "application/json"
+        || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+/// Names the offending field of a deserialization error and describes the
+/// problem without parser positions.
+///
+/// Serde reports a missing field at its parent, so its name is taken from
+/// the message instead.
+fn describe(path: &serde_path_to_error::Path, err: &impl std::fmt::Display) -> (String, String) {
+    let mut message = err.to_string();
+    if let Some(at) = message.find(" at line ") {
+        message.truncate(at);
+    }
+
+    let parent = path.to_string();
+    let parent = match parent.as_str() {
+        "." | "?" => None,
+        _ => Some(parent),
+    };
+    let missing = message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.strip_suffix('`'))
+        .map(str::to_string);
+
+    let field = match (parent, missing) {
+        (Some(parent), Some(missing)) => format!("{parent}.{missing}"),
+        (None, Some(missing)) => missing,
+        (Some(parent), None) => parent,
+        (None, None) => "body".to_string(),
+    };
+    (field, message)
+}
+
+fn validation_error(errors: ValidationErrors) -> Error {
+    Error::unprocessable_entity(
+        errors
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |e| {
+                    let message = e.message.clone().unwrap_or_else(|| e.code.clone());
+                    (Cow::Borrowed(field), message)
+                })
+            }),
+    )
+}
@@ -0,0 +1,178 @@
+use crate::config::Config;
+use crate::db::{Migrated, MIGRATOR};
+use crate::AppState;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+use sqlx::migrate::Migrate;
+use sqlx::postgres::PgPool;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(liveness))
+        .route("/readyz", get(readiness))
+}
+
+/// Set when a shutdown signal arrives, failing readiness from then on.
+#[derive(Clone, Default)]
+pub struct ShuttingDown(Arc<AtomicBool>);
+
+impl ShuttingDown {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn set(&self) {
+        self.0.store(true, Ordering::Release)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Fail,
+}
+
+#[derive(Serialize)]
+struct Report {
+    status: Status,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(Serialize)]
+struct Check {
+    status: Status,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl IntoResponse for Report {
+    fn into_response(self) -> Response {
+        let status_code = match self.status {
+            Status::Ok => StatusCode::OK,
+            Status::Fail => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status_code, Json(self)).into_response()
+    }
+}
+
+async fn liveness() -> Report {
+    Report {
+        status: Status::Ok,
+        checks: BTreeMap::new(),
+    }
+}
+
+async fn readiness(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<Config>>,
+    State(migrated): State<Migrated>,
+    State(shutting_down): State<ShuttingDown>,
+) -> Report {
+    let timeout = config.health.readiness_timeout;
+    let mut checks = BTreeMap::new();
+
+    checks.insert(
+        "shutdown",
+        check(timeout, async {
+            if shutting_down.get() {
+                return Err("shutting down".to_string());
+            }
+            Ok(())
+        })
+        .await,
+    );
+    checks.insert(
+        "database",
+        check(timeout, async {
+            let mut conn = pool
+                .acquire()
+                .await
+                .map_err(failed("database unreachable"))?;
+            sqlx::query("SELECT 1")
+                .execute(&mut conn)
+                .await
+                .map_err(failed("database unreachable"))?;
+            Ok(())
+        })
+        .await,
+    );
+    checks.insert(
+        "migrations",
+        check(timeout, async {
+            if !migrated.get() {
+                return Err("migrations have not been applied yet".to_string());
+            }
+            // Someone may have reverted migrations since startup.
+            let mut conn = pool
+                .acquire()
+                .await
+                .map_err(failed("migrations could not be checked"))?;
+            let applied = conn
+                .list_applied_migrations()
+                .await
+                .map_err(failed("migrations could not be checked"))?;
+            for migration in MIGRATOR
+                .iter()
+                .filter(|m| !m.migration_type.is_down_migration())
+            {
+                if !applied
+                    .iter()
+                    .any(|a| a.version == migration.version && a.checksum == migration.checksum)
+                {
+                    return Err(format!("migration {} is not applied", migration.version));
+                }
+            }
+            Ok(())
+        })
+        .await,
+    );
+
+    let status = if checks.values().all(|c| matches!(c.status, Status::Ok)) {
+        Status::Ok
+    } else {
+        Status::Fail
+    };
+    Report { status, checks }
+}
+
+/// Logs why a check failed and returns `message` for the report, which
+/// must not expose database error details.
+fn failed<E: std::fmt::Display>(message: &'static str) -> impl FnOnce(E) -> String {
+    move |err| {
+        tracing::warn!("readiness check failed: {message}: {err}");
+        message.to_string()
+    }
+}
+
+async fn check(timeout: Duration, f: impl Future<Output = Result<(), String>>) -> Check {
+    let start = Instant::now();
+    let result = tokio::time::timeout(timeout, f)
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {timeout:?}")));
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    match result {
+        Ok(()) => Check {
+            status: Status::Ok,
+            latency_ms,
+            error: None,
+        },
+        Err(error) => Check {
+            status: Status::Fail,
+            latency_ms,
+            error: Some(error),
+        },
+    }
+}
@@ -0,0 +1,190 @@
+//! `Idempotency-Key` support: the response to the first request with a key
+//! is stored and replayed for retries carrying the same key and payload.
+
+use crate::config::IdempotencyConfig;
+use crate::db::{self, Migrated};
+use crate::error::Error;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::postgres::{PgPool, Postgres};
+use sqlx::types::Json;
+use sqlx::Transaction;
+use std::collections::BTreeMap;
+
+static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+static IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// The `Idempotency-Key` of the request, if it has one.
+pub fn key(headers: &HeaderMap) -> Result<Option<String>, Error> {
+    let Some(value) = headers.get(&IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key) if (1..=255).contains(&key.len()) => Ok(Some(key.to_string())),
+        _ => Err(Error::bad_request(
+            "invalid header",
+            [(
+                "idempotency-key",
+                "must be 1 to 255 visible ASCII characters",
+            )],
+        )),
+    }
+}
+
+/// Identifies a request payload, to tell retries apart from key reuse.
+pub fn fingerprint(payload: &impl Serialize) -> Vec<u8> {
+    let json = serde_json::to_vec(payload).expect("payloads serialize to JSON");
+    Sha256::digest(json).to_vec()
+}
+
+pub enum Claim {
+    /// The key is new: handle the request and [`save`] its response.
+    Proceed,
+    /// The key was used before for the same payload.
+    Replay(Response),
+}
+
+#[derive(sqlx::FromRow)]
+struct Record {
+    request_hash: Vec<u8>,
+    status: Option<i16>,
+    headers: Option<Json<BTreeMap<String, String>>>,
+    body: Option<Vec<u8>>,
+}
+
+/// Claims `key` within `scope` for the transaction. A concurrent request
+/// with the same key blocks until the transaction ends, then replays its
+/// response; if the transaction rolls back, the key is free again.
+pub async fn claim(
+    tx: &mut Transaction<'_, Postgres>,
+    config: &IdempotencyConfig,
+    scope: &str,
+    key: &str,
+    fingerprint: &[u8],
+) -> Result<Claim, Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM idempotency_keys
+        WHERE scope = $1 AND key = $2 AND created_at < now() - make_interval(secs => $3)
+        "#,
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(config.ttl.as_secs_f64())
+    .execute(&mut *tx)
+    .await?;
+
+    let claimed = sqlx::query(
+        r#"
+        INSERT INTO idempotency_keys (scope, key, request_hash)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (scope, key) DO NOTHING
+        "#,
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(fingerprint)
+    .execute(&mut *tx)
+    .await?;
+    if claimed.rows_affected() == 1 {
+        return Ok(Claim::Proceed);
+    }
+
+    let record = sqlx::query_as::<_, Record>(
+        r#"
+        SELECT request_hash, status, headers, body
+        FROM idempotency_keys
+        WHERE scope = $1 AND key = $2
+        "#,
+    )
+    .bind(scope)
+    .bind(key)
+    .fetch_optional(&mut *tx)
+    .await?
+    // Expired and purged in between.
+    .ok_or(Error::Retryable)?;
+
+    if record.request_hash != fingerprint {
+        return Err(Error::unprocessable_entity([(
+            "idempotency-key",
+            "was already used for a different request",
+        )]));
+    }
+    let (Some(status), Some(Json(headers)), Some(body)) =
+        (record.status, record.headers, record.body)
+    else {
+        return Err(Error::Conflict(
+            "a request with the same idempotency key is still being processed".into(),
+        ));
+    };
+    let status = StatusCode::from_u16(status as u16).map_err(anyhow::Error::from)?;
+    let mut response = response(status, &headers, body);
+    response.headers_mut().insert(
+        IDEMPOTENT_REPLAYED.clone(),
+        HeaderValue::from_static("true"),
+    );
+    Ok(Claim::Replay(response))
+}
+
+/// Stores the response for a key claimed in the same transaction.
+pub async fn save(
+    tx: &mut Transaction<'_, Postgres>,
+    scope: &str,
+    key: &str,
+    status: StatusCode,
+    headers: &BTreeMap<String, String>,
+    body: &[u8],
+) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+        UPDATE idempotency_keys
+        SET status = $3, headers = $4, body = $5
+        WHERE scope = $1 AND key = $2
+        "#,
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(status.as_u16() as i16)
+    .bind(Json(headers))
+    .bind(body)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Builds a JSON response from the parts [`save`] stores.
+pub fn response(status: StatusCode, headers: &BTreeMap<String, String>, body: Vec<u8>) -> Response {
+    let mut response = (
+        status,
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        )],
+        body,
+    )
+        .into_response();
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Deletes stored responses older than `ttl`, every `purge_interval`.
+pub async fn purge_expired(pool: PgPool, config: IdempotencyConfig, migrated: Migrated) {
+    db::purge_periodically(
+        pool,
+        migrated,
+        "DELETE FROM idempotency_keys WHERE created_at < now() - make_interval(secs => $1)",
+        config.ttl,
+        config.purge_interval,
+        "expired idempotency key(s)",
+    )
+    .await
+}
@@ -0,0 +1,52 @@
+use crate::config::PaginationConfig;
+use crate::error::Error;
+use axum::http::{HeaderValue, Uri};
+
+/// Resolves the requested page size against the configured default and
+/// maximum. Larger sizes are capped rather than rejected.
+pub fn limit(config: &PaginationConfig, requested: Option<i64>) -> Result<i64, Error> {
+    match requested {
+        None => Ok(config.default_limit),
+        Some(limit) if limit < 1 => Err(Error::bad_request(
+            "invalid query parameter",
+            [("limit", "must be at least 1")],
+        )),
+        Some(limit) => Ok(limit.min(config.max_limit)),
+    }
+}
+
+pub fn offset(requested: Option<i64>) -> Result<i64, Error> {
+    match requested {
+        Some(offset) if offset < 0 => Err(Error::bad_request(
+            "invalid query parameter",
+            [("offset", "must not be negative")],
+        )),
+        offset => Ok(offset.unwrap_or_default()),
+    }
+}
+
+/// Builds an RFC 8288 `Link` header pointing at other pages of `uri`.
+///
+/// Each link keeps the query parameters of the current request except the
+/// ones it overrides, so filters carry over between pages.
+pub fn link_header(uri: &Uri, links: &[(&str, Vec<(&str, String)>)]) -> Option<HeaderValue> {
+    let links: Vec<_> = links
+        .iter()
+        .map(|(rel, overrides)| {
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            for (key, value) in form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+                if !overrides.iter().any(|(k, _)| *k == key) {
+                    query.append_pair(&key, &value);
+                }
+            }
+            for (key, value) in overrides {
+                query.append_pair(key, value);
+            }
+            format!("<{}?{}>; rel=\"{rel}\"", uri.path(), query.finish())
+        })
+        .collect();
+    if links.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&links.join(", ")).ok()
+}
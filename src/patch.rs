@@ -0,0 +1,417 @@
+//! JSON Merge Patch (RFC 7396) and JSON Patch (RFC 6902) documents, applied
+//! to a resource's JSON representation.
+
+use crate::error::Error;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+pub const MERGE_PATCH: &str = "application/merge-patch+json";
+pub const JSON_PATCH: &str = "application/json-patch+json";
+
+pub enum Patch {
+    Merge(Value),
+    Json(Vec<Operation>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+impl Patch {
+    /// Applies the patch to `doc`. JSON Patch operations apply in order and
+    /// leave `doc` untouched if any of them fails.
+    pub fn apply(&self, doc: &mut Value) -> Result<(), Error> {
+        match self {
+            Self::Merge(patch) => merge(doc, patch),
+            Self::Json(operations) => {
+                let mut patched = doc.clone();
+                for (i, operation) in operations.iter().enumerate() {
+                    operation.apply(&mut patched).map_err(|e| match e {
+                        OpError::Invalid(field, message) => {
+                            Error::unprocessable_entity([(format!("[{i}].{field}"), message)])
+                        }
+                        OpError::TestFailed => {
+                            Error::Conflict(format!("test operation [{i}] failed").into())
+                        }
+                    })?;
+                }
+                *doc = patched;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!()
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge(target.entry(key.as_str()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+enum OpError {
+    /// The operation member at fault and what is wrong with it.
+    Invalid(&'static str, String),
+    TestFailed,
+}
+
+impl Operation {
+    fn apply(&self, doc: &mut Value) -> Result<(), OpError> {
+        match self {
+            Self::Add { path, value } => add(doc, path, value.clone()).map_err(at("path")),
+            Self::Remove { path } => remove(doc, path).map(drop).map_err(at("path")),
+            Self::Replace { path, value } => {
+                let target = doc.pointer_mut(path).ok_or_else(|| missing(path))?;
+                *target = value.clone();
+                Ok(())
+            }
+            Self::Move { from, path } => {
+                if path.starts_with(&format!("{from}/")) {
+                    return Err(OpError::Invalid(
+                        "path",
+                        "must not be a child of `from`".to_string(),
+                    ));
+                }
+                let value = remove(doc, from).map_err(at("from"))?;
+                add(doc, path, value).map_err(at("path"))
+            }
+            Self::Copy { from, path } => {
+                let value = doc
+                    .pointer(from)
+                    .cloned()
+                    .ok_or_else(|| OpError::Invalid("from", format!("`{from}` does not exist")))?;
+                add(doc, path, value).map_err(at("path"))
+            }
+            Self::Test { path, value } => match doc.pointer(path) {
+                Some(actual) if actual == value => Ok(()),
+                Some(_) => Err(OpError::TestFailed),
+                None => Err(missing(path)),
+            },
+        }
+    }
+}
+
+fn at(field: &'static str) -> impl Fn(String) -> OpError {
+    move |message| OpError::Invalid(field, message)
+}
+
+fn missing(path: &str) -> OpError {
+    OpError::Invalid("path", format!("`{path}` does not exist"))
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    let Some((parent, key)) = split(path)? else {
+        *doc = value;
+        return Ok(());
+    };
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(key, value);
+        }
+        Some(Value::Array(array)) if key == "-" => array.push(value),
+        Some(Value::Array(array)) => match index(&key) {
+            Some(i) if i <= array.len() => array.insert(i, value),
+            _ => return Err(format!("`{path}` is not a valid array index")),
+        },
+        Some(_) => return Err(format!("`{parent}` is not an object or array")),
+        None => return Err(format!("`{parent}` does not exist")),
+    }
+    Ok(())
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    let Some((parent, key)) = split(path)? else {
+        return Err("the whole document cannot be removed".to_string());
+    };
+    let removed = match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => map.remove(&key),
+        Some(Value::Array(array)) => match index(&key) {
+            Some(i) if i < array.len() => Some(array.remove(i)),
+            _ => None,
+        },
+        _ => None,
+    };
+    removed.ok_or_else(|| format!("`{path}` does not exist"))
+}
+
+/// Splits a JSON Pointer (RFC 6901) into the pointer to its parent and its
+/// last, unescaped reference token; `None` for the whole document.
+fn split(path: &str) -> Result<Option<(&str, String)>, String> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+    if !path.starts_with('/') {
+        return Err(format!("`{path}` is not a JSON Pointer"));
+    }
+    let at = path.rfind('/').unwrap_or_default();
+    let key = path[at + 1..].replace("~1", "/").replace("~0", "~");
+    Ok(Some((&path[..at], key)))
+}
+
+fn index(token: &str) -> Option<usize> {
+    if token.is_empty()
+        || !token.bytes().all(|b| b.is_ascii_digit())
+        || (token.len() > 1 && token.starts_with('0'))
+    {
+        return None;
+    }
+    token.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn json_patch(mut doc: Value, operations: Value) -> Result<Value, Error> {
+        let operations = serde_json::from_value(operations).expect("valid operations");
+        Patch::Json(operations).apply(&mut doc)?;
+        Ok(doc)
+    }
+
+    fn merge_patch(mut doc: Value, patch: Value) -> Value {
+        Patch::Merge(patch).apply(&mut doc).unwrap();
+        doc
+    }
+
+    // RFC 6902, Appendix A.
+
+    #[test]
+    fn adds_an_object_member() {
+        let doc = json_patch(
+            json!({"foo": "bar"}),
+            json!([{"op": "add", "path": "/baz", "value": "qux"}]),
+        );
+        assert_eq!(doc.unwrap(), json!({"baz": "qux", "foo": "bar"}));
+    }
+
+    #[test]
+    fn adds_an_array_element() {
+        let doc = json_patch(
+            json!({"foo": ["bar", "baz"]}),
+            json!([{"op": "add", "path": "/foo/1", "value": "qux"}]),
+        );
+        assert_eq!(doc.unwrap(), json!({"foo": ["bar", "qux", "baz"]}));
+    }
+
+    #[test]
+    fn removes_an_object_member() {
+        let doc = json_patch(
+            json!({"baz": "qux", "foo": "bar"}),
+            json!([{"op": "remove", "path": "/baz"}]),
+        );
+        assert_eq!(doc.unwrap(), json!({"foo": "bar"}));
+    }
+
+    #[test]
+    fn removes_an_array_element() {
+        let doc = json_patch(
+            json!({"foo": ["bar", "qux", "baz"]}),
+            json!([{"op": "remove", "path": "/foo/1"}]),
+        );
+        assert_eq!(doc.unwrap(), json!({"foo": ["bar", "baz"]}));
+    }
+
+    #[test]
+    fn replaces_a_value() {
+        let doc = json_patch(
+            json!({"baz": "qux", "foo": "bar"}),
+            json!([{"op": "replace", "path": "/baz", "value": "boo"}]),
+        );
+        assert_eq!(doc.unwrap(), json!({"baz": "boo", "foo": "bar"}));
+    }
+
+    #[test]
+    fn moves_a_value() {
+        let doc = json_patch(
+            json!({
+                "foo": {"bar": "baz", "waldo": "fred"},
+                "qux": {"corge": "grault"}
+            }),
+            json!([{"op": "move", "from": "/foo/waldo", "path": "/qux/thud"}]),
+        );
+        assert_eq!(
+            doc.unwrap(),
+            json!({
+                "foo": {"bar": "baz"},
+                "qux": {"corge": "grault", "thud": "fred"}
+            })
+        );
+    }
+
+    #[test]
+    fn moves_an_array_element() {
+        let doc = json_patch(
+            json!({"foo": ["all", "grass", "cows", "eat"]}),
+            json!([{"op": "move", "from": "/foo/1", "path": "/foo/3"}]),
+        );
+        assert_eq!(
+            doc.unwrap(),
+            json!({"foo": ["all", "cows", "eat", "grass"]})
+        );
+    }
+
+    #[test]
+    fn tests_a_value() {
+        let doc = json_patch(
+            json!({"baz": "qux", "foo": ["a", 2, "c"]}),
+            json!([
+                {"op": "test", "path": "/baz", "value": "qux"},
+                {"op": "test", "path": "/foo/1", "value": 2}
+            ]),
+        );
+        assert_eq!(doc.unwrap(), json!({"baz": "qux", "foo": ["a", 2, "c"]}));
+    }
+
+    #[test]
+    fn fails_a_test() {
+        let err = json_patch(
+            json!({"baz": "qux"}),
+            json!([{"op": "test", "path": "/baz", "value": "bar"}]),
+        );
+        assert!(matches!(err, Err(Error::Conflict(_))));
+    }
+
+    #[test]
+    fn adds_a_nested_member_object() {
+        let doc = json_patch(
+            json!({"foo": "bar"}),
+            json!([{"op": "add", "path": "/child", "value": {"grandchild": {}}}]),
+        );
+        assert_eq!(
+            doc.unwrap(),
+            json!({"foo": "bar", "child": {"grandchild": {}}})
+        );
+    }
+
+    #[test]
+    fn ignores_unrecognized_members() {
+        let doc = json_patch(
+            json!({"foo": "bar"}),
+            json!([{"op": "add", "path": "/baz", "value": "qux", "xyz": 123}]),
+        );
+        assert_eq!(doc.unwrap(), json!({"foo": "bar", "baz": "qux"}));
+    }
+
+    #[test]
+    fn rejects_adding_to_a_nonexistent_target() {
+        let err = json_patch(
+            json!({"foo": "bar"}),
+            json!([{"op": "add", "path": "/baz/bat", "value": "qux"}]),
+        );
+        assert!(matches!(err, Err(Error::UnprocessableEntity { .. })));
+    }
+
+    #[test]
+    fn unescapes_pointers_in_order() {
+        let doc = json_patch(
+            json!({"/": 9, "~1": 10}),
+            json!([{"op": "test", "path": "/~01", "value": 10}]),
+        );
+        assert_eq!(doc.unwrap(), json!({"/": 9, "~1": 10}));
+    }
+
+    #[test]
+    fn compares_strings_and_numbers_strictly() {
+        let err = json_patch(
+            json!({"/": 9, "~1": 10}),
+            json!([{"op": "test", "path": "/~01", "value": "10"}]),
+        );
+        assert!(matches!(err, Err(Error::Conflict(_))));
+    }
+
+    #[test]
+    fn adds_an_array_value() {
+        let doc = json_patch(
+            json!({"foo": ["bar"]}),
+            json!([{"op": "add", "path": "/foo/-", "value": ["abc", "def"]}]),
+        );
+        assert_eq!(doc.unwrap(), json!({"foo": ["bar", ["abc", "def"]]}));
+    }
+
+    #[test]
+    fn leaves_the_document_untouched_on_failure() {
+        let mut doc = json!({"foo": "bar"});
+        let operations = serde_json::from_value(json!([
+            {"op": "add", "path": "/baz", "value": "qux"},
+            {"op": "remove", "path": "/missing"}
+        ]))
+        .unwrap();
+        assert!(Patch::Json(operations).apply(&mut doc).is_err());
+        assert_eq!(doc, json!({"foo": "bar"}));
+    }
+
+    // RFC 7396, Appendix A.
+
+    #[test]
+    fn merges_the_rfc_examples() {
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (target, patch, expected) in cases {
+            assert_eq!(merge_patch(target, patch.clone()), expected, "{patch}");
+        }
+    }
+}
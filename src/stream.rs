@@ -0,0 +1,132 @@
+//! Response bodies produced incrementally, e.g. from a database cursor,
+//! without holding the whole response in memory.
+
+use crate::error::Error;
+use axum::body::{Bytes, StreamBody};
+use axum::BoxError;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::Instrument;
+
+/// Chunks in flight between the producer and the connection. Once they are
+/// all unsent, the producer waits for the client to catch up.
+const CHANNEL_CAPACITY: usize = 8;
+const CHUNK_SIZE: usize = 8 * 1024;
+
+pub type Body = StreamBody<ReceiverStream<Result<Bytes, BoxError>>>;
+
+/// The client has gone away or stopped reading, so there is no point
+/// producing more.
+#[derive(thiserror::Error, Debug)]
+#[error("the client closed the connection or stopped reading")]
+pub struct Closed;
+
+/// Bounds how many responses stream at once. Each one holds a pooled
+/// connection until it ends, so unbounded streams would starve every other
+/// request of connections. A client that stops reading for `send_timeout`
+/// is given up on, so stalled clients can't hold slots forever.
+#[derive(Clone)]
+pub struct Streams {
+    semaphore: Arc<Semaphore>,
+    send_timeout: Duration,
+}
+
+/// A slot reserved with [`Streams::permit`], freed when the stream ends.
+pub struct Permit {
+    _permit: OwnedSemaphorePermit,
+    send_timeout: Duration,
+}
+
+impl Streams {
+    pub fn new(max: usize, send_timeout: Duration) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            send_timeout,
+        }
+    }
+
+    /// Reserves a slot for a stream, or fails with 503 when none is free.
+    pub fn permit(&self) -> Result<Permit, Error> {
+        self.semaphore
+            .clone()
+            .try_acquire_owned()
+            .map(|permit| Permit {
+                _permit: permit,
+                send_timeout: self.send_timeout,
+            })
+            .map_err(|_| Error::ServiceUnavailable)
+    }
+}
+
+/// Buffers writes into chunks of about [`CHUNK_SIZE`] bytes for the body.
+pub struct Sink {
+    tx: mpsc::Sender<Result<Bytes, BoxError>>,
+    buf: Vec<u8>,
+    send_timeout: Duration,
+}
+
+impl Sink {
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Closed> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Closed> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buf,
+            Vec::with_capacity(CHUNK_SIZE),
+        ));
+        self.tx
+            .send_timeout(Ok(chunk), self.send_timeout)
+            .await
+            .map_err(|_| Closed)
+    }
+}
+
+/// Runs `produce` on its own task and streams what it writes as the body,
+/// holding `permit` until it is done.
+///
+/// The status and headers are sent before the body, so an error part-way
+/// through can only be logged and the response cut short, which clients see
+/// as a truncated body.
+pub fn spawn<F, Fut>(permit: Permit, produce: F) -> Body
+where
+    F: FnOnce(Sink) -> Fut,
+    Fut: Future<Output = anyhow::Result<Sink>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let sink = Sink {
+        tx: tx.clone(),
+        buf: Vec::with_capacity(CHUNK_SIZE),
+        send_timeout: permit.send_timeout,
+    };
+    let task = produce(sink);
+    tokio::spawn(
+        async move {
+            let _permit = permit;
+            let result = match task.await {
+                Ok(mut sink) => sink.flush().await.map_err(anyhow::Error::from),
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => {}
+                Err(err) if err.is::<Closed>() => tracing::debug!("{err}"),
+                Err(err) => {
+                    tracing::error!("failed to stream the response: {err:?}");
+                    let _ = tx.send(Err(err.into())).await;
+                }
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+    StreamBody::new(ReceiverStream::new(rx))
+}
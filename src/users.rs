@@ -1,119 +1,1362 @@
+use crate::config::{Config, UsersConfig};
+use crate::cursor;
+use crate::db::{self, Migrated};
 use crate::error::Error;
-use crate::error::ResultExt;
-use axum::Extension;
+use crate::error::{DbErrorKind, FieldErrors, ResultExt};
+use crate::etag::{self, ETag};
+use crate::extract::{self, Json, Path, Query, RawBody, ValidatedJson};
+use crate::idempotency::{self, Claim};
+use crate::pagination;
+use crate::patch::Patch;
+use crate::stream::{self, Streams};
+use crate::AppState;
+use anyhow::anyhow;
 use axum::{
-    extract::{Json, Path, Query},
-    http::StatusCode,
-    routing::{get, post, put},
+    extract::{DefaultBodyLimit, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put},
     Router,
 };
+use futures_util::TryStreamExt;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgPool;
+use serde_json::Value;
+use sqlx::postgres::{PgConnection, PgPool, Postgres};
+use sqlx::{Connection, QueryBuilder, Transaction};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
 
-pub fn router() -> Router {
+pub fn router(config: &UsersConfig) -> Router<AppState> {
+    // The static routes below shadow `/user/:name` for the names in
+    // `RESERVED`. Other methods on them mean a user that cannot exist.
     Router::new()
         .route("/user/:name", get(get_user))
+        .route(
+            "/user/id/:id",
+            get(get_user_by_id).fallback(|| async { Error::NotFound }),
+        )
         .route("/user", get(get_users))
+        .route(
+            "/user/search",
+            get(search_users).fallback(|| async { Error::NotFound }),
+        )
+        .route(
+            "/user/bulk",
+            post(bulk_create_users)
+                .layer(DefaultBodyLimit::max(config.bulk_body_limit))
+                .fallback(|| async { Error::NotFound }),
+        )
+        .route(
+            "/user/export",
+            get(export_users).fallback(|| async { Error::NotFound }),
+        )
         .route("/user/:name", put(update_user))
+        .route("/user/:name", patch(patch_user))
         .route("/user", post(create_user))
+        .route("/user/:name", delete(delete_user))
+        .route("/user/:name/restore", post(restore_user))
 }
 
-#[derive(sqlx::FromRow, Serialize, Deserialize)]
+static USERNAME: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[a-zA-Z0-9_.-]+$").unwrap());
+
+/// Path segments under `/user/` taken by other routes, so no user can be
+/// addressed by them.
+const RESERVED: &[&str] = &["search", "export", "bulk", "id"];
+
+// The rules for each user field, shared by every payload that sets it.
+
+fn validate_username(username: &str) -> Result<(), ValidationError> {
+    if !(3..=32).contains(&username.chars().count()) {
+        return Err(invalid("length", "must be 3 to 32 characters long"));
+    }
+    if !USERNAME.is_match(username) {
+        return Err(invalid(
+            "regex",
+            "may only contain letters, digits, '_', '.' and '-'",
+        ));
+    }
+    if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(username)) {
+        return Err(invalid("reserved", "is reserved"));
+    }
+    Ok(())
+}
+
+fn validate_email(email: &str) -> Result<(), ValidationError> {
+    if !validator::validate_email(email) {
+        return Err(invalid("email", "must be a valid email address"));
+    }
+    if email.chars().count() > 254 {
+        return Err(invalid("length", "must be at most 254 characters long"));
+    }
+    Ok(())
+}
+
+fn validate_bio(bio: &str) -> Result<(), ValidationError> {
+    if bio.chars().count() > 1024 {
+        return Err(invalid("length", "must be at most 1024 characters long"));
+    }
+    // Reserved for marking search matches, see `highlight`.
+    if bio.contains([MARK_START, MARK_STOP]) {
+        return Err(invalid("characters", "must not contain U+E000 or U+E001"));
+    }
+    Ok(())
+}
+
+fn invalid(code: &'static str, message: &'static str) -> ValidationError {
+    let mut err = ValidationError::new(code);
+    err.message = Some(message.into());
+    err
+}
+
+#[derive(sqlx::FromRow, Serialize)]
 struct User {
+    id: Uuid,
     username: String,
     email: String,
-    bio: String,
+    bio: Option<String>,
+    /// Exposed as the `ETag` of the user resource rather than in the body.
+    #[serde(skip)]
+    version: i64,
 }
 
-#[derive(Deserialize)]
+impl User {
+    /// Includes the id so a user recreated under the same name never
+    /// matches a tag cached for its predecessor.
+    fn etag(&self) -> ETag {
+        ETag::new(format_args!("{}-{}", self.id.simple(), self.version))
+    }
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+struct NewUser {
+    #[validate(custom = "validate_username")]
+    username: String,
+    #[validate(custom = "validate_email")]
+    email: String,
+    #[validate(custom = "validate_bio")]
+    bio: Option<String>,
+}
+
+#[derive(Deserialize, Validate)]
 struct UserUpdate {
+    #[validate(custom = "validate_username")]
+    username: Option<String>,
+    #[validate(custom = "validate_email")]
     email: Option<String>,
+    #[validate(custom = "validate_bio")]
     bio: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct DeleteOptions {
+    permanent: Option<bool>,
+}
+
+/// Offset pagination by default; passing `cursor` (empty for the first
+/// page) switches to keyset pagination in the requested sort order.
+/// `total=true` adds the number of matching users to the response.
+///
+/// `stream` instead returns every matching user in one response, streamed
+/// in the given format, and cannot be combined with the other parameters.
 #[derive(Deserialize)]
 struct Pagination {
-    offset: Option<i32>,
-    limit: Option<i32>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    total: Option<bool>,
+    stream: Option<StreamFormat>,
+}
+
+/// Sorting and filtering of the user list. Ties on the sort column are
+/// broken by `username`, so every order is total and cursor-safe.
+#[derive(Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    sort: Sort,
+    #[serde(default)]
+    order: Order,
+    username: Option<String>,
+    username_prefix: Option<String>,
+    email_domain: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    created_after: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    created_before: Option<OffsetDateTime>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Sort {
+    #[default]
+    Username,
+    Email,
+    CreatedAt,
+}
+
+impl Sort {
+    fn column(self) -> &'static str {
+        match self {
+            Self::Username => "username",
+            Self::Email => "email",
+            Self::CreatedAt => "created_at",
+        }
+    }
+
+    fn sql_type(self) -> &'static str {
+        match self {
+            Self::Username | Self::Email => "text",
+            Self::CreatedAt => "timestamptz",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl Order {
+    fn reverse(self) -> Self {
+        match self {
+            Self::Asc => Self::Desc,
+            Self::Desc => Self::Asc,
+        }
+    }
+
+    fn sql(self) -> &'static str {
+        match self {
+            Self::Asc => "ASC",
+            Self::Desc => "DESC",
+        }
+    }
+
+    /// The comparison selecting rows that come after a key in this order.
+    fn after(self) -> &'static str {
+        match self {
+            Self::Asc => ">",
+            Self::Desc => "<",
+        }
+    }
+}
+
+impl ListQuery {
+    /// Appends the filters as `AND` conditions to a query that already has
+    /// a `WHERE` clause.
+    fn push_filters(&self, query: &mut QueryBuilder<'_, Postgres>) {
+        if let Some(username) = &self.username {
+            query.push(" AND username = ").push_bind(username.clone());
+        }
+        if let Some(prefix) = &self.username_prefix {
+            query
+                .push(" AND username LIKE ")
+                .push_bind(format!("{}%", escape_like(prefix)));
+        }
+        if let Some(domain) = &self.email_domain {
+            query
+                .push(" AND lower(split_part(email, '@', 2)) = lower(")
+                .push_bind(domain.clone())
+                .push(")");
+        }
+        if let Some(after) = self.created_after {
+            query.push(" AND created_at > ").push_bind(after);
+        }
+        if let Some(before) = self.created_before {
+            query.push(" AND created_at < ").push_bind(before);
+        }
+    }
+
+    fn push_order_by(&self, query: &mut QueryBuilder<'_, Postgres>, order: Order) {
+        query.push(format_args!(
+            " ORDER BY {column} {order}, username {order}",
+            column = self.sort.column(),
+            order = order.sql(),
+        ));
+    }
+}
+
+/// Escapes the `LIKE` wildcards so the value only matches literally.
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[derive(Serialize)]
+struct OffsetPage {
+    items: Vec<User>,
+    offset: i64,
+    limit: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<i64>,
+}
+
+/// Where a cursor page starts: the sort key and username of the row next to
+/// it, in the sort order the cursor was issued for.
+#[derive(Serialize, Deserialize)]
+struct Position {
+    sort: Sort,
+    order: Order,
+    direction: Direction,
+    key: String,
+    username: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Direction {
+    After,
+    Before,
+}
+
+#[derive(Serialize)]
+struct CursorPage {
+    items: Vec<User>,
+    limit: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<i64>,
+    next: Option<String>,
+    prev: Option<String>,
 }
 
 async fn get_user(
-    Extension(pool): Extension<PgPool>,
+    State(pool): State<PgPool>,
     Path(name): Path<String>,
-) -> Result<Json<User>, Error> {
+    headers: HeaderMap,
+) -> Result<Response, Error> {
     let user = sqlx::query_as::<_, User>(
         r#"
-        SELECT username, email, bio 
+        SELECT id, username, email, bio, version
         FROM users 
-        WHERE username = $1
+        WHERE username = $1 AND deleted_at IS NULL
         "#,
     )
     .bind(name)
     .fetch_optional(&pool)
     .await?
     .ok_or(Error::NotFound)?;
-    Ok(Json(user))
+    Ok(conditional_get(&headers, user))
+}
+
+async fn get_user_by_id(
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        SELECT id, username, email, bio, version
+        FROM users
+        WHERE id = $1 AND deleted_at IS NULL
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(Error::NotFound)?;
+    Ok(conditional_get(&headers, user))
+}
+
+/// Answers `304 Not Modified` when `If-None-Match` names the user's
+/// current `ETag`, and the user otherwise. Both carry the `ETag`.
+fn conditional_get(headers: &HeaderMap, user: User) -> Response {
+    let etag = user.etag();
+    if etag::if_none_match(headers, &etag) {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag.header_value())],
+        )
+            .into_response();
+    }
+    ([(header::ETAG, etag.header_value())], Json(user)).into_response()
 }
 
 async fn get_users(
-    Extension(pool): Extension<PgPool>,
+    State(pool): State<PgPool>,
+    State(config): State<Arc<Config>>,
+    State(streams): State<Streams>,
+    uri: Uri,
     Query(pagination): Query<Pagination>,
-) -> Result<Json<Vec<User>>, Error> {
-    let user = sqlx::query_as::<_, User>(
+    Query(query): Query<ListQuery>,
+) -> Result<Response, Error> {
+    if let Some(format) = pagination.stream {
+        if pagination.offset.is_some()
+            || pagination.limit.is_some()
+            || pagination.cursor.is_some()
+            || pagination.total.is_some()
+        {
+            return Err(Error::bad_request(
+                "invalid query parameter",
+                [("stream", "cannot be combined with pagination parameters")],
+            ));
+        }
+        let mut builder = QueryBuilder::new(
+            "SELECT id, username, email, bio, version FROM users WHERE deleted_at IS NULL",
+        );
+        query.push_filters(&mut builder);
+        query.push_order_by(&mut builder, query.order);
+        return stream_users(pool, &streams, builder, format);
+    }
+
+    let limit = pagination::limit(&config.pagination, pagination.limit)?;
+    let total = match pagination.total {
+        Some(true) => Some(count_users(&pool, &query).await?),
+        _ => None,
+    };
+
+    if let Some(cursor) = &pagination.cursor {
+        let secret = config.pagination.cursor_secret.as_bytes();
+        let position = match cursor.as_str() {
+            "" => None,
+            cursor => Some(cursor::decode::<Position>(secret, cursor).ok_or_else(|| {
+                Error::bad_request(
+                    "invalid query parameter",
+                    [("cursor", "is malformed or has been tampered with")],
+                )
+            })?),
+        };
+        if let Some(position) = &position {
+            if position.sort != query.sort || position.order != query.order {
+                return Err(Error::bad_request(
+                    "invalid query parameter",
+                    [("cursor", "was issued for a different sort order")],
+                ));
+            }
+        }
+        let page = get_users_page(&pool, &query, position, limit).await?;
+        let next = page.next.map(|p| cursor::encode(secret, &p));
+        let prev = page.prev.map(|p| cursor::encode(secret, &p));
+
+        let mut links = Vec::new();
+        if let Some(next) = &next {
+            links.push(("next", vec![("cursor", next.clone())]));
+        }
+        if let Some(prev) = &prev {
+            links.push(("prev", vec![("cursor", prev.clone())]));
+        }
+        let body = CursorPage {
+            items: page.items,
+            limit,
+            total,
+            next,
+            prev,
+        };
+        return Ok(with_links(&uri, links, body));
+    }
+
+    let offset = pagination::offset(pagination.offset)?;
+    let mut builder = QueryBuilder::new(
+        "SELECT id, username, email, bio, version FROM users WHERE deleted_at IS NULL",
+    );
+    query.push_filters(&mut builder);
+    query.push_order_by(&mut builder, query.order);
+    builder
+        .push(" OFFSET ")
+        .push_bind(offset)
+        .push(" LIMIT ")
+        .push_bind(limit + 1);
+    let mut items = builder.build_query_as::<User>().fetch_all(&pool).await?;
+
+    let mut links = Vec::new();
+    if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        links.push((
+            "next",
+            vec![
+                ("offset", (offset + limit).to_string()),
+                ("limit", limit.to_string()),
+            ],
+        ));
+    }
+    if offset > 0 {
+        links.push((
+            "prev",
+            vec![
+                ("offset", (offset - limit).max(0).to_string()),
+                ("limit", limit.to_string()),
+            ],
+        ));
+    }
+    let body = OffsetPage {
+        items,
+        offset,
+        limit,
+        total,
+    };
+    Ok(with_links(&uri, links, body))
+}
+
+fn with_links(
+    uri: &Uri,
+    links: Vec<(&str, Vec<(&str, String)>)>,
+    body: impl Serialize,
+) -> Response {
+    let mut response = Json(body).into_response();
+    if let Some(link) = pagination::link_header(uri, &links) {
+        response.headers_mut().insert(header::LINK, link);
+    }
+    response
+}
+
+async fn count_users(pool: &PgPool, query: &ListQuery) -> Result<i64, Error> {
+    let mut builder = QueryBuilder::new("SELECT count(*) FROM users WHERE deleted_at IS NULL");
+    query.push_filters(&mut builder);
+    let (total,) = builder.build_query_as().fetch_one(pool).await?;
+    Ok(total)
+}
+
+/// A user plus its sort key as text, which is what goes into cursors.
+#[derive(sqlx::FromRow)]
+struct KeyedUser {
+    #[sqlx(flatten)]
+    user: User,
+    sort_key: String,
+}
+
+struct Page {
+    items: Vec<User>,
+    next: Option<Position>,
+    prev: Option<Position>,
+}
+
+/// Fetches up to `limit` users from `position`, one extra row telling
+/// whether there is more in that direction.
+async fn get_users_page(
+    pool: &PgPool,
+    query: &ListQuery,
+    position: Option<Position>,
+    limit: i64,
+) -> Result<Page, Error> {
+    let column = query.sort.column();
+    let backwards = matches!(&position, Some(p) if p.direction == Direction::Before);
+    // Paging backwards reads the rows in reverse and flips them afterwards.
+    let order = if backwards {
+        query.order.reverse()
+    } else {
+        query.order
+    };
+
+    let mut builder = QueryBuilder::new(format!(
+        "SELECT id, username, email, bio, version, {column}::text AS sort_key \
+         FROM users WHERE deleted_at IS NULL"
+    ));
+    query.push_filters(&mut builder);
+    if let Some(position) = &position {
+        builder
+            .push(format_args!(
+                " AND ({column}, username) {} (",
+                order.after()
+            ))
+            .push_bind(position.key.clone())
+            .push(format_args!("::{}, ", query.sort.sql_type()))
+            .push_bind(position.username.clone())
+            .push(")");
+    }
+    query.push_order_by(&mut builder, order);
+    builder.push(" LIMIT ").push_bind(limit + 1);
+    let mut rows = builder
+        .build_query_as::<KeyedUser>()
+        .fetch_all(pool)
+        .await?;
+
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    if backwards {
+        rows.reverse();
+    }
+
+    // Coming back from a later page there always is a next one, and anything
+    // but the first page has a previous one.
+    let (has_next, has_prev) = if backwards {
+        (true, has_more)
+    } else {
+        (has_more, position.is_some())
+    };
+    let at = |row: &KeyedUser, direction| Position {
+        sort: query.sort,
+        order: query.order,
+        direction,
+        key: row.sort_key.clone(),
+        username: row.user.username.clone(),
+    };
+    Ok(Page {
+        next: rows
+            .last()
+            .filter(|_| has_next)
+            .map(|row| at(row, Direction::After)),
+        prev: rows
+            .first()
+            .filter(|_| has_prev)
+            .map(|row| at(row, Direction::Before)),
+        items: rows.into_iter().map(|row| row.user).collect(),
+    })
+}
+
+/// `ts_headline` marks matches with these private-use characters, which
+/// [`highlight`] turns into `<mark>` once the field text is HTML-escaped.
+const MARK_START: char = '\u{E000}';
+const MARK_STOP: char = '\u{E001}';
+const HIGHLIGHT_FIELD: &str = "StartSel=\u{E000}, StopSel=\u{E001}, HighlightAll=true";
+const HIGHLIGHT_SNIPPET: &str =
+    "StartSel=\u{E000}, StopSel=\u{E001}, MaxFragments=2, MaxWords=20, MinWords=5";
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+}
+
+#[derive(sqlx::FromRow)]
+struct SearchRow {
+    #[sqlx(flatten)]
+    user: User,
+    rank: f32,
+    username_highlight: String,
+    email_highlight: String,
+    bio_highlight: String,
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    #[serde(flatten)]
+    user: User,
+    rank: f32,
+    /// The matching fields only, with the matches marked.
+    highlights: BTreeMap<&'static str, String>,
+}
+
+#[derive(Serialize)]
+struct SearchResults {
+    items: Vec<SearchHit>,
+    limit: i64,
+}
+
+/// Ranked search over username, email and bio. Whole words and phrases are
+/// matched against the `search` tsvector, word prefixes let partial names
+/// match, and trigram similarity catches typos in usernames and emails.
+async fn search_users(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<Config>>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResults>, Error> {
+    let limit = pagination::limit(&config.pagination, query.limit)?;
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err(Error::bad_request(
+            "invalid query parameter",
+            [("q", "must not be empty")],
+        ));
+    }
+    if q.chars().count() > 256 {
+        return Err(Error::bad_request(
+            "invalid query parameter",
+            [("q", "must be at most 256 characters long")],
+        ));
+    }
+
+    let rows = sqlx::query_as::<_, SearchRow>(
         r#"
-        SELECT username, email, bio 
-        FROM users 
-        OFFSET $1 LIMIT $2
+        WITH q AS (
+            SELECT websearch_to_tsquery('english', $1) || to_tsquery('simple', $2) AS query
+        )
+        SELECT id, username, email, bio, version,
+            (ts_rank(search, query)
+                + greatest(word_similarity($1, username), word_similarity($1, email)))::real
+                AS rank,
+            ts_headline('english', username, query, $3) AS username_highlight,
+            ts_headline('english', email, query, $3) AS email_highlight,
+            ts_headline('english', coalesce(bio, ''), query, $4) AS bio_highlight
+        FROM users, q
+        WHERE deleted_at IS NULL
+            AND (search @@ query
+                OR $1 <% username OR $1 <% email
+                OR username % $1 OR email % $1)
+        ORDER BY rank DESC, username
+        LIMIT $5
         "#,
     )
-    .bind(pagination.offset.unwrap_or_default())
-    .bind(pagination.limit.unwrap_or(i32::MAX))
+    .bind(q)
+    .bind(prefix_query(q))
+    .bind(HIGHLIGHT_FIELD)
+    .bind(HIGHLIGHT_SNIPPET)
+    .bind(limit)
     .fetch_all(&pool)
     .await?;
-    Ok(Json(user))
+
+    let items = rows
+        .into_iter()
+        .map(|row| {
+            let highlights = [
+                ("username", row.username_highlight),
+                ("email", row.email_highlight),
+                ("bio", row.bio_highlight),
+            ]
+            .into_iter()
+            .filter(|(_, text)| text.contains(MARK_START))
+            .map(|(field, text)| (field, highlight(&text)))
+            .collect();
+            SearchHit {
+                user: row.user,
+                rank: row.rank,
+                highlights,
+            }
+        })
+        .collect();
+    Ok(Json(SearchResults { items, limit }))
+}
+
+/// HTML-escapes a `ts_headline` result and wraps its marked matches in
+/// `<mark>`, so the highlights are safe to render as HTML.
+fn highlight(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            MARK_START => html.push_str("<mark>"),
+            MARK_STOP => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Turns every word of the search text into a prefix term, `da eve` into
+/// `da:* & eve:*`. Only letters and digits are kept, so the result is always
+/// valid `to_tsquery` syntax.
+fn prefix_query(q: &str) -> String {
+    q.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(" & ")
 }
 
+/// With an `Idempotency-Key`, retrying a request that already succeeded
+/// replays the stored response instead of failing on the taken username.
 async fn create_user(
-    Extension(pool): Extension<PgPool>,
-    Json(payload): Json<User>,
-) -> Result<StatusCode, Error> {
-    sqlx::query(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+    ValidatedJson(payload): ValidatedJson<NewUser>,
+) -> Result<Response, Error> {
+    const SCOPE: &str = "create_user";
+
+    let key = idempotency::key(&headers)?;
+    let mut tx = pool.begin().await?;
+    if let Some(key) = &key {
+        let fingerprint = idempotency::fingerprint(&payload);
+        let claim = idempotency::claim(&mut tx, &config.idempotency, SCOPE, key, &fingerprint);
+        if let Claim::Replay(response) = claim.await? {
+            return Ok(response);
+        }
+    }
+
+    let user = insert_user(&mut tx, &payload).await?;
+
+    let headers = BTreeMap::from([
+        (
+            header::LOCATION.to_string(),
+            format!("/user/{}", user.username),
+        ),
+        (header::ETAG.to_string(), user.etag().to_string()),
+    ]);
+    let body = serde_json::to_vec(&user).map_err(anyhow::Error::from)?;
+    if let Some(key) = &key {
+        idempotency::save(&mut tx, SCOPE, key, StatusCode::CREATED, &headers, &body).await?;
+    }
+    tx.commit().await?;
+    Ok(idempotency::response(StatusCode::CREATED, &headers, body))
+}
+
+async fn insert_user(conn: &mut PgConnection, user: &NewUser) -> Result<User, Error> {
+    let result = sqlx::query_as::<_, User>(
         r#"
         INSERT INTO users (username, email, bio) 
         VALUES ($1, $2, $3)
+        returning id, username, email, bio, version
+        "#,
+    )
+    .bind(&user.username)
+    .bind(&user.email)
+    .bind(&user.bio)
+    .fetch_one(conn)
+    .await;
+    map_taken(result)
+}
+
+/// Reports a clash on a unique user column as that field being taken. Every
+/// write path goes through here, so a new unique column is mapped once.
+fn map_taken<T>(result: Result<T, impl Into<Error>>) -> Result<T, Error> {
+    result
+        .on_constraint("user_username_key", |_| {
+            Error::unprocessable_entity([("username", "already taken")])
+        })
+        .on_constraint("user_email_key", |_| {
+            Error::unprocessable_entity([("email", "already taken")])
+        })
+        .on_db_error(DbErrorKind::UniqueViolation, |dbe| {
+            tracing::warn!(
+                "unique constraint {:?} has no field mapping",
+                dbe.constraint()
+            );
+            sqlx::Error::Database(dbe).into()
+        })
+}
+
+/// Rows per multi-row `INSERT`, well below Postgres' limit of 65535 bind
+/// parameters.
+const BULK_CHUNK_SIZE: usize = 500;
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum BulkResult {
+    Created { index: usize, user: User },
+    Failed { index: usize, errors: FieldErrors },
+}
+
+impl BulkResult {
+    fn index(&self) -> usize {
+        match self {
+            Self::Created { index, .. } | Self::Failed { index, .. } => *index,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BulkReport {
+    created: usize,
+    failed: usize,
+    results: Vec<BulkResult>,
+}
+
+/// Creates users from a JSON array or NDJSON, one user per line. A row that
+/// does not parse, validate or insert is reported by its zero-based index
+/// among the rows and does not stop the others, which are inserted in one
+/// transaction. The body is read whole, up to `users.bulk_body_limit` bytes;
+/// larger imports have to be split across requests.
+async fn bulk_create_users(
+    State(pool): State<PgPool>,
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> Result<Json<BulkReport>, Error> {
+    let mut results = Vec::new();
+    let mut rows = Vec::new();
+    for (index, row) in parse_bulk(&headers, &body)?.into_iter().enumerate() {
+        match row {
+            Ok(user) => rows.push((index, user)),
+            Err(err) => results.push(BulkResult::Failed {
+                index,
+                errors: row_errors(err)?,
+            }),
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+    for chunk in rows.chunks(BULK_CHUNK_SIZE) {
+        insert_chunk(&mut tx, chunk, &mut results).await?;
+    }
+    tx.commit().await?;
+
+    results.sort_by_key(BulkResult::index);
+    let created = results
+        .iter()
+        .filter(|r| matches!(r, BulkResult::Created { .. }))
+        .count();
+    Ok(Json(BulkReport {
+        created,
+        failed: results.len() - created,
+        results,
+    }))
+}
+
+fn parse_bulk(headers: &HeaderMap, body: &[u8]) -> Result<Vec<Result<NewUser, Error>>, Error> {
+    match extract::media_type(headers).as_deref() {
+        Some("application/json") => {
+            let values: Vec<Value> = extract::from_slice(body)?;
+            Ok(values.into_iter().map(extract::from_value).collect())
+        }
+        Some("application/x-ndjson" | "application/ndjson") => Ok(body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .map(|line| extract::from_slice(line).and_then(extract::from_value))
+            .collect()),
+        _ => Err(Error::bad_request(
+            "expected a request with `Content-Type: application/json` \
+             or `application/x-ndjson`",
+            [(
+                "content-type",
+                "must be application/json or application/x-ndjson",
+            )],
+        )),
+    }
+}
+
+/// The field errors of a problem with a single row; anything else fails
+/// the whole request.
+fn row_errors(err: Error) -> Result<FieldErrors, Error> {
+    match err {
+        Error::UnprocessableEntity { errors } | Error::BadRequest { errors, .. } => Ok(errors),
+        err => Err(err),
+    }
+}
+
+/// Inserts a chunk of rows with one statement. If that fails, the rows are
+/// retried one by one to tell which of them are at fault.
+async fn insert_chunk(
+    tx: &mut Transaction<'_, Postgres>,
+    chunk: &[(usize, NewUser)],
+    results: &mut Vec<BulkResult>,
+) -> Result<(), Error> {
+    let mut builder = QueryBuilder::new("INSERT INTO users (username, email, bio) ");
+    builder.push_values(chunk, |mut row, (_, user)| {
+        row.push_bind(&user.username)
+            .push_bind(&user.email)
+            .push_bind(&user.bio);
+    });
+    builder.push(" returning id, username, email, bio, version");
+
+    let mut savepoint = tx.begin().await?;
+    match builder
+        .build_query_as::<User>()
+        .fetch_all(&mut savepoint)
+        .await
+    {
+        Ok(users) => {
+            savepoint.commit().await?;
+            let mut users: HashMap<_, _> = users
+                .into_iter()
+                .map(|user| (user.username.clone(), user))
+                .collect();
+            for (index, row) in chunk {
+                let user = users
+                    .remove(&row.username)
+                    .ok_or_else(|| anyhow!("user {} missing from RETURNING", row.username))?;
+                results.push(BulkResult::Created {
+                    index: *index,
+                    user,
+                });
+            }
+            return Ok(());
+        }
+        Err(sqlx::Error::Database(_)) => savepoint.rollback().await?,
+        Err(err) => return Err(err.into()),
+    }
+
+    for (index, row) in chunk {
+        let mut savepoint = tx.begin().await?;
+        match insert_user(&mut savepoint, row).await {
+            Ok(user) => {
+                savepoint.commit().await?;
+                results.push(BulkResult::Created {
+                    index: *index,
+                    user,
+                });
+            }
+            Err(err) => {
+                savepoint.rollback().await?;
+                results.push(BulkResult::Failed {
+                    index: *index,
+                    errors: row_errors(err)?,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encodings of responses streamed row by row.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StreamFormat {
+    Json,
+    #[default]
+    Ndjson,
+    Csv,
+}
+
+#[derive(Deserialize)]
+struct ExportOptions {
+    #[serde(default)]
+    format: StreamFormat,
+}
+
+/// Streams every user, as NDJSON unless another `format` is asked for.
+async fn export_users(
+    State(pool): State<PgPool>,
+    State(streams): State<Streams>,
+    Query(options): Query<ExportOptions>,
+) -> Result<Response, Error> {
+    let filename = match options.format {
+        StreamFormat::Json => "users.json",
+        StreamFormat::Ndjson => "users.ndjson",
+        StreamFormat::Csv => "users.csv",
+    };
+    let query = QueryBuilder::new(
+        "SELECT id, username, email, bio, version FROM users WHERE deleted_at IS NULL \
+         ORDER BY username",
+    );
+    let mut response = stream_users(pool, &streams, query, options.format)?;
+    let disposition = format!("attachment; filename=\"{filename}\"");
+    response.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::try_from(disposition).expect("file names are plain ASCII"),
+    );
+    Ok(response)
+}
+
+/// Streams the users `query` selects straight from a database cursor. Rows
+/// are encoded as they arrive, so memory use stays flat however many match,
+/// and a client reading slowly holds the cursor back instead of rows piling
+/// up in the server. Fails with 503 when `streams` has no slot left.
+fn stream_users(
+    pool: PgPool,
+    streams: &Streams,
+    mut query: QueryBuilder<'static, Postgres>,
+    format: StreamFormat,
+) -> Result<Response, Error> {
+    let permit = streams.permit()?;
+    let content_type = match format {
+        StreamFormat::Json => "application/json",
+        StreamFormat::Ndjson => "application/x-ndjson",
+        StreamFormat::Csv => "text/csv; charset=utf-8",
+    };
+    let body = stream::spawn(permit, move |mut sink| async move {
+        match format {
+            StreamFormat::Json => sink.write(b"[").await?,
+            StreamFormat::Ndjson => {}
+            StreamFormat::Csv => sink.write(b"id,username,email,bio\r\n").await?,
+        }
+        let mut users = query.build_query_as::<User>().fetch(&pool);
+        let mut line = Vec::new();
+        let mut first = true;
+        while let Some(user) = users.try_next().await? {
+            line.clear();
+            match format {
+                StreamFormat::Json => {
+                    if !first {
+                        line.push(b',');
+                    }
+                    serde_json::to_writer(&mut line, &user)?;
+                }
+                StreamFormat::Ndjson => {
+                    serde_json::to_writer(&mut line, &user)?;
+                    line.push(b'\n');
+                }
+                StreamFormat::Csv => {
+                    let id = user.id.to_string();
+                    let bio = user.bio.as_deref().unwrap_or_default();
+                    let fields = [id.as_str(), &user.username, &user.email, bio];
+                    for (i, field) in fields.into_iter().enumerate() {
+                        if i > 0 {
+                            line.push(b',');
+                        }
+                        write_csv_field(&mut line, field);
+                    }
+                    line.extend_from_slice(b"\r\n");
+                }
+            }
+            first = false;
+            sink.write(&line).await?;
+        }
+        if let StreamFormat::Json = format {
+            sink.write(b"]").await?;
+        }
+        Ok(sink)
+    });
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+/// Writes a CSV field (RFC 4180), quoted if it contains a delimiter, quote
+/// or line break. Fields a spreadsheet would run as a formula are prefixed
+/// with `'` and quoted, so opening an export can't execute user input.
+fn write_csv_field(out: &mut Vec<u8>, field: &str) {
+    let formula = field.starts_with(['=', '+', '-', '@', '\t', '\r']);
+    if formula || field.contains([',', '"', '\r', '\n']) {
+        out.push(b'"');
+        if formula {
+            out.push(b'\'');
+        }
+        out.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+        out.push(b'"');
+    } else {
+        out.extend_from_slice(field.as_bytes());
+    }
+}
+
+/// Applies the update only if `If-Match`, when given, names the current
+/// `ETag` of the user; the row stays locked between the check and the write.
+async fn update_user(
+    State(pool): State<PgPool>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    ValidatedJson(payload): ValidatedJson<UserUpdate>,
+) -> Result<impl IntoResponse, Error> {
+    let mut tx = pool.begin().await?;
+    let current = lock_user(&mut tx, &name, &headers).await?;
+
+    let result = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users
+        SET username = coalesce($1, users.username),
+            email = coalesce($2, users.email),
+            bio = coalesce($3, users.bio),
+            version = users.version + 1
+        WHERE id = $4
+        returning id, username, email, bio, version
         "#,
     )
     .bind(payload.username)
     .bind(payload.email)
     .bind(payload.bio)
-    .execute(&pool)
-    .await
-    .on_constraint("user_username_key", |_| {
-        Error::unprocessable_entity([("username", "already taken")])
-    })
-    .on_constraint("user_email_key", |_| {
-        Error::unprocessable_entity([("email", "already taken")])
-    })?;
-    Ok(StatusCode::CREATED)
+    .bind(current.id)
+    .fetch_one(&mut tx)
+    .await;
+    let user = map_taken(result)?;
+    tx.commit().await?;
+    Ok(with_etag(user))
 }
 
-async fn update_user(
-    Extension(pool): Extension<PgPool>,
+/// The full editable representation of a user, which patches are applied
+/// to and the result validated against.
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct UserDocument {
+    id: Uuid,
+    #[validate(custom = "validate_username")]
+    username: String,
+    #[validate(custom = "validate_email")]
+    email: String,
+    #[validate(custom = "validate_bio")]
+    bio: Option<String>,
+}
+
+/// Applies a JSON Merge Patch or JSON Patch to the user's JSON
+/// representation and stores the result if it is still a valid user with
+/// the same id. `If-Match` is honoured like for `PUT`.
+async fn patch_user(
+    State(pool): State<PgPool>,
     Path(name): Path<String>,
-    Json(payload): Json<UserUpdate>,
+    headers: HeaderMap,
+    patch: Patch,
+) -> Result<impl IntoResponse, Error> {
+    let mut tx = pool.begin().await?;
+    let current = lock_user(&mut tx, &name, &headers).await?;
+
+    let mut document = serde_json::to_value(&current).map_err(anyhow::Error::from)?;
+    patch.apply(&mut document)?;
+    let document: UserDocument = extract::from_value(document)?;
+    if document.id != current.id {
+        return Err(Error::unprocessable_entity([("id", "cannot be changed")]));
+    }
+
+    let result = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users
+        SET username = $1, email = $2, bio = $3, version = users.version + 1
+        WHERE id = $4
+        returning id, username, email, bio, version
+        "#,
+    )
+    .bind(document.username)
+    .bind(document.email)
+    .bind(document.bio)
+    .bind(current.id)
+    .fetch_one(&mut tx)
+    .await;
+    let user = map_taken(result)?;
+    tx.commit().await?;
+    Ok(with_etag(user))
+}
+
+/// Loads a user for modification, locking its row for the rest of the
+/// transaction, and checks `If-Match` against its current `ETag`.
+async fn lock_user(
+    tx: &mut Transaction<'_, Postgres>,
+    name: &str,
+    headers: &HeaderMap,
+) -> Result<User, Error> {
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        SELECT id, username, email, bio, version
+        FROM users
+        WHERE username = $1 AND deleted_at IS NULL
+        FOR UPDATE
+        "#,
+    )
+    .bind(name)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+    if !etag::if_match(headers, &user.etag()) {
+        return Err(Error::PreconditionFailed);
+    }
+    Ok(user)
+}
+
+fn with_etag(user: User) -> impl IntoResponse {
+    ([(header::ETAG, user.etag().header_value())], Json(user))
+}
+
+async fn delete_user(
+    State(pool): State<PgPool>,
+    State(config): State<Arc<Config>>,
+    Path(name): Path<String>,
+    Query(options): Query<DeleteOptions>,
 ) -> Result<StatusCode, Error> {
-    sqlx::query(
+    let result = if options.permanent.unwrap_or(!config.users.soft_delete) {
+        sqlx::query(
+            r#"
+            DELETE FROM users
+            WHERE username = $1
+            "#,
+        )
+        .bind(name)
+        .execute(&pool)
+        .await?
+    } else {
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET deleted_at = now()
+            WHERE username = $1 AND deleted_at IS NULL
+            "#,
+        )
+        .bind(name)
+        .execute(&pool)
+        .await?
+    };
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn restore_user(
+    State(pool): State<PgPool>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let user = sqlx::query_as::<_, User>(
         r#"
         UPDATE users
-        SET email = coalesce($1, users.email), bio = coalesce($2, users.bio)
-        WHERE username = $3
-        returning email, username, bio
+        SET deleted_at = NULL, version = version + 1
+        WHERE username = $1 AND deleted_at IS NOT NULL
+        returning id, username, email, bio, version
         "#,
     )
-    .bind(payload.email)
-    .bind(payload.bio)
     .bind(name)
-    .execute(&pool)
-    .await?;
-    Ok(StatusCode::ACCEPTED)
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(Error::NotFound)?;
+    Ok(with_etag(user))
+}
+
+/// Permanently removes users that were soft-deleted more than
+/// `purge_retention` ago, every `purge_interval`.
+pub async fn purge_deleted(pool: PgPool, config: UsersConfig, migrated: Migrated) {
+    db::purge_periodically(
+        pool,
+        migrated,
+        "DELETE FROM users WHERE deleted_at < now() - make_interval(secs => $1)",
+        config.purge_retention,
+        config.purge_interval,
+        "deleted user(s)",
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv_field(field: &str) -> String {
+        let mut out = Vec::new();
+        write_csv_field(&mut out, field);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn escapes_highlighted_text() {
+        let text = format!("<script>alert('{MARK_START}x{MARK_STOP}')</script> & \"y\"");
+        assert_eq!(
+            highlight(&text),
+            "&lt;script&gt;alert(&#39;<mark>x</mark>&#39;)&lt;/script&gt; &amp; &quot;y&quot;"
+        );
+    }
+
+    #[test]
+    fn marks_every_match() {
+        let text = format!("{MARK_START}ada{MARK_STOP} and {MARK_START}eve{MARK_STOP}");
+        assert_eq!(highlight(&text), "<mark>ada</mark> and <mark>eve</mark>");
+        assert_eq!(highlight("no matches"), "no matches");
+    }
+
+    #[test]
+    fn rejects_bios_with_highlight_markers() {
+        assert!(validate_bio("plain bio").is_ok());
+        assert!(validate_bio(&format!("fake {MARK_START}mark{MARK_STOP}")).is_err());
+    }
+
+    #[test]
+    fn builds_prefix_queries() {
+        assert_eq!(prefix_query("da eve"), "da:* & eve:*");
+        assert_eq!(
+            prefix_query("  Ada.Lovelace@x.io "),
+            "ada:* & lovelace:* & x:* & io:*"
+        );
+    }
+
+    #[test]
+    fn strips_tsquery_syntax_from_prefix_queries() {
+        assert_eq!(
+            prefix_query("a & !b | (c:*) <-> 'd'"),
+            "a:* & b:* & c:* & d:*"
+        );
+        assert_eq!(prefix_query("&|!():*<->'\\"), "");
+        assert_eq!(prefix_query(""), "");
+    }
+
+    #[test]
+    fn keeps_unicode_words_in_prefix_queries() {
+        assert_eq!(prefix_query("Zoë Ünal"), "zoë:* & ünal:*");
+        assert_eq!(prefix_query("東京 😀"), "東京:*");
+    }
+
+    #[test]
+    fn writes_plain_csv_fields_verbatim() {
+        assert_eq!(csv_field("alice"), "alice");
+        assert_eq!(csv_field("a-b@example.com"), "a-b@example.com");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn quotes_csv_fields_with_delimiters() {
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn neutralises_csv_formulas() {
+        assert_eq!(csv_field("=1+1"), "\"'=1+1\"");
+        assert_eq!(csv_field("+1"), "\"'+1\"");
+        assert_eq!(csv_field("-1"), "\"'-1\"");
+        assert_eq!(csv_field("@SUM(A1)"), "\"'@SUM(A1)\"");
+        assert_eq!(csv_field("\tx"), "\"'\tx\"");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+    }
 }